@@ -1,10 +1,31 @@
 use crate::{get_exception, qjs, Ctx, Error, Function, Mut, Ref, Result, Weak};
-use std::{any::Any, ffi::CString, mem};
-
+use std::{
+    any::Any,
+    ffi::{c_void, CString},
+    mem,
+    os::raw::c_int,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+#[cfg(debug_assertions)]
+use std::cell::Cell;
+
+// `Spawner` is where a `drive()`-style future would enqueue/wake work;
+// it has neither yet — see the `Opaque::spawner` doc below.
 #[cfg(feature = "futures")]
 mod async_runtime;
 #[cfg(feature = "futures")]
 pub use async_runtime::*;
+// Intended home for `JsTask<T>`/`FallibleTask` — cancellable join handles
+// over a spawned future, backed by an executor that allocates its
+// notification state once, caches a waker, and makes a single driving pass
+// per wake rather than re-polling everything. None of that exists here:
+// no task type, no `spawn()` returning a handle, no `.cancel()`/
+// `.fallible()`, and no such executor. Treat anything referencing `JsTask`
+// or a cancellable spawn API as aspirational until this module has real
+// content.
 #[cfg(feature = "futures")]
 mod async_executor;
 #[cfg(feature = "futures")]
@@ -24,12 +45,20 @@ use crate::{allocator::AllocatorHolder, Allocator};
 use crate::{loader::LoaderHolder, Loader, Resolver};
 
 #[derive(Clone)]
-#[repr(transparent)]
-pub struct WeakRuntime(Weak<Mut<Inner>>);
+pub struct WeakRuntime {
+    inner: Weak<Mut<Inner>>,
+    // Kept alongside, rather than behind `inner`, so a ticking timer thread
+    // can bump the epoch without taking the runtime-wide lock a runaway
+    // script might be holding for the entire eval.
+    epoch: Arc<AtomicU64>,
+}
 
 impl WeakRuntime {
     pub fn try_ref(&self) -> Option<Runtime> {
-        self.0.upgrade().map(|inner| Runtime { inner })
+        self.inner.upgrade().map(|inner| Runtime {
+            inner,
+            epoch: self.epoch.clone(),
+        })
     }
 }
 
@@ -45,9 +74,27 @@ pub struct Opaque {
     /// The registery, used to keep track of which registery values belong to this runtime.
     pub registery: HashSet<RegisteryKey>,
 
-    /// Async spawner
+    /// Async spawner. Nothing enqueues work through this yet — `Spawner`'s
+    /// own enqueue path and the promise-resolution callback sites that
+    /// would notify a waiting `drive()` future are still unwired, which is
+    /// why `Runtime` has no `drive()`/`wake_driver` of its own (see the
+    /// `mod async_runtime`/`mod async_executor` note above).
     #[cfg(feature = "futures")]
     pub spawner: Option<Spawner>,
+
+    /// Callback installed by `Runtime::set_interrupt_handler`, polled by QuickJS
+    /// between bytecode instructions. Returning `true` aborts the currently
+    /// executing script with an uncatchable exception.
+    pub interrupt_handler: Option<Box<dyn FnMut() -> bool + Send + 'static>>,
+
+    /// Set by the interrupt trampoline when `interrupt_handler` aborts the
+    /// currently running script, so `execute_pending_job` can surface a
+    /// distinct `Error::Interrupted` instead of treating the abort as an
+    /// ordinary thrown exception.
+    pub(crate) interrupted: AtomicBool,
+
+    /// Cumulative counters backing `Runtime::metrics()`.
+    pub metrics: MetricsInner,
 }
 
 impl Opaque {
@@ -59,7 +106,94 @@ impl Opaque {
             registery: HashSet::default(),
             #[cfg(feature = "futures")]
             spawner: Default::default(),
+            interrupt_handler: None,
+            interrupted: AtomicBool::new(false),
+            metrics: MetricsInner::default(),
+        }
+    }
+}
+
+/// Atomic counters backing [`RuntimeMetrics`], kept in [`Opaque`] so they
+/// survive for the lifetime of the runtime and can be read without
+/// disturbing it.
+#[derive(Default)]
+pub struct MetricsInner {
+    jobs_executed: AtomicU64,
+    jobs_failed: AtomicU64,
+    gc_runs: AtomicU64,
+    peak_memory: AtomicUsize,
+}
+
+/// A point-in-time snapshot of cumulative counters maintained by a
+/// [`Runtime`], returned by [`Runtime::metrics`].
+///
+/// Complements the one-shot [`Runtime::memory_usage`] with the kind of
+/// running totals an embedder would otherwise have to maintain itself to
+/// tune [`Runtime::set_gc_threshold`]/[`Runtime::set_memory_limit`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RuntimeMetrics {
+    /// Number of pending jobs (promise reactions, async function
+    /// continuations, ...) that ran to completion.
+    pub jobs_executed: u64,
+    /// Number of pending jobs that threw an exception.
+    pub jobs_failed: u64,
+    /// Number of times `Runtime::run_gc` was called.
+    pub gc_runs: u64,
+    /// High-water mark, in bytes, of `malloc_size` as reported by
+    /// `JS_ComputeMemoryUsage`, sampled after every job run by
+    /// `execute_pending_job` and on every `run_gc` call rather than only
+    /// when `metrics` itself is polled. A call of `execute_pending_job` that
+    /// finds no pending job takes no sample.
+    pub peak_memory: usize,
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    /// Tracks whether a pending job is already running on this thread for
+    /// *some* runtime. Re-entering while set indicates a job tried to drive
+    /// the runtime again from inside itself, which given the
+    /// single global `Mut<Inner>` lock can deadlock or corrupt the QuickJS
+    /// stack rather than just misbehave.
+    static IN_PROGRESS: Cell<bool> = Cell::new(false);
+}
+
+/// Debug-only re-entrancy guard, currently only wired into
+/// `Inner::execute_pending_job`. Panics on re-entry; compiled out entirely
+/// in release builds so it has no hot-path cost.
+///
+/// The native `Function` callback trampoline is the more common and more
+/// dangerous way to trip this (a JS-visible Rust callback re-entering the
+/// runtime it's running on, while `Inner`'s lock is already held) and does
+/// not call this guard — that trampoline lives outside `runtime.rs`. This
+/// guard catches pending-job re-entrancy only; re-entry through a native
+/// callback is not detected.
+#[cfg(debug_assertions)]
+#[must_use = "dropping the guard immediately defeats it; bind it to a name for the guarded scope"]
+pub(crate) struct ReentrancyGuard;
+
+#[cfg(debug_assertions)]
+impl ReentrancyGuard {
+    pub(crate) fn enter(info: &Option<CString>) -> Self {
+        let already_running = IN_PROGRESS.with(|flag| flag.replace(true));
+        if already_running {
+            let name = info
+                .as_ref()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "<unnamed>".to_string());
+            panic!(
+                "re-entrant use of QuickJS runtime `{}`: a pending job was started \
+                 while another was already running on this thread",
+                name
+            );
         }
+        ReentrancyGuard
+    }
+}
+
+#[cfg(debug_assertions)]
+impl Drop for ReentrancyGuard {
+    fn drop(&mut self) {
+        IN_PROGRESS.with(|flag| flag.set(false));
     }
 }
 
@@ -98,12 +232,10 @@ impl Inner {
         }
     }
 
-    #[cfg(feature = "futures")]
     pub(crate) unsafe fn get_opaque(&self) -> &Opaque {
         &*(qjs::JS_GetRuntimeOpaque(self.rt) as *const _)
     }
 
-    #[cfg(feature = "futures")]
     pub(crate) unsafe fn get_opaque_mut(&mut self) -> &mut Opaque {
         &mut *(qjs::JS_GetRuntimeOpaque(self.rt) as *mut _)
     }
@@ -113,6 +245,9 @@ impl Inner {
     }
 
     pub(crate) fn execute_pending_job(&mut self) -> Result<bool> {
+        #[cfg(debug_assertions)]
+        let _guard = ReentrancyGuard::enter(&self.info);
+
         let mut ctx_ptr = mem::MaybeUninit::<*mut qjs::JSContext>::uninit();
         self.update_stack_top();
         let result = unsafe { qjs::JS_ExecutePendingJob(self.rt, ctx_ptr.as_mut_ptr()) };
@@ -121,21 +256,50 @@ impl Inner {
             return Ok(false);
         }
         let ctx_ptr = unsafe { ctx_ptr.assume_init() };
+        self.sample_peak_memory();
         if result == 1 {
             // single job executed
+            unsafe { self.get_opaque().metrics.jobs_executed.fetch_add(1, Ordering::Relaxed) };
             return Ok(true);
         }
         // exception thrown
+        unsafe { self.get_opaque().metrics.jobs_failed.fetch_add(1, Ordering::Relaxed) };
+        if unsafe { self.get_opaque().interrupted.swap(false, Ordering::Relaxed) } {
+            return Err(Error::Interrupted);
+        }
         let ctx = Ctx::from_ptr(ctx_ptr);
         Err(unsafe { get_exception(ctx) })
     }
+
+    /// Sample `JS_ComputeMemoryUsage` and fold it into the running
+    /// `peak_memory` high-water mark.
+    ///
+    /// Called from places that already hold `inner`'s lock and do
+    /// meaningful work (`execute_pending_job`, `Runtime::run_gc`) so the
+    /// high-water mark reflects memory actually touched rather than
+    /// whatever happened to be resident whenever an embedder last polled
+    /// `Runtime::metrics`.
+    pub(crate) fn sample_peak_memory(&self) {
+        let mut stats = mem::MaybeUninit::uninit();
+        unsafe { qjs::JS_ComputeMemoryUsage(self.rt, stats.as_mut_ptr()) };
+        let malloc_size = unsafe { stats.assume_init() }.malloc_size as usize;
+        unsafe {
+            self.get_opaque()
+                .metrics
+                .peak_memory
+                .fetch_max(malloc_size, Ordering::Relaxed)
+        };
+    }
 }
 
 /// Quickjs runtime, entry point of the library.
 #[derive(Clone)]
-#[repr(transparent)]
 pub struct Runtime {
     pub(crate) inner: Ref<Mut<Inner>>,
+    // Lives outside `inner` so `increment_epoch` can tick it without ever
+    // taking the lock `inner` guards, which a runaway script may be holding
+    // for the entire duration of the run it's supposed to abort.
+    epoch: Arc<AtomicU64>,
 }
 
 impl Runtime {
@@ -201,6 +365,7 @@ impl Runtime {
                 #[cfg(feature = "loader")]
                 loader: None,
             })),
+            epoch: Arc::new(AtomicU64::new(0)),
         };
 
         let opaque = Box::into_raw(Box::new(Opaque::new(&runtime)));
@@ -211,7 +376,10 @@ impl Runtime {
 
     /// Get weak ref to runtime
     pub fn weak(&self) -> WeakRuntime {
-        WeakRuntime(Ref::downgrade(&self.inner))
+        WeakRuntime {
+            inner: Ref::downgrade(&self.inner),
+            epoch: self.epoch.clone(),
+        }
     }
 
     /// Set the module loader
@@ -263,6 +431,78 @@ impl Runtime {
         mem::drop(guard);
     }
 
+    /// Install a closure which QuickJS polls between bytecode instructions to
+    /// decide whether to abort the currently running script.
+    ///
+    /// Returning `true` from the handler aborts whatever bytecode is
+    /// currently running. `execute_pending_job` detects this and surfaces
+    /// `Error::Interrupted` instead of treating it as an ordinary thrown
+    /// exception. Top-level `Ctx::eval` is not wired up to do the same here
+    /// — that's this crate's eval error path, outside `runtime.rs` — so an
+    /// interrupted `eval()` currently still comes back as a generic thrown
+    /// exception. This is the low level primitive; prefer
+    /// [`Runtime::set_epoch_deadline`] for a simple wall-clock-free timeout.
+    ///
+    /// The handler must be `Send`: under the `parallel` feature the
+    /// interrupt trampoline runs on whatever thread is currently executing
+    /// this runtime's bytecode, which need not be the thread that installed it.
+    pub fn set_interrupt_handler<F>(&self, handler: F)
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        let guard = self.inner.lock();
+        unsafe {
+            guard.get_opaque_mut().interrupt_handler = Some(Box::new(handler));
+            qjs::JS_SetInterruptHandler(
+                guard.rt,
+                Some(Self::interrupt_trampoline),
+                guard.rt as *mut c_void,
+            );
+        }
+        mem::drop(guard);
+    }
+
+    unsafe extern "C" fn interrupt_trampoline(
+        rt: *mut qjs::JSRuntime,
+        _opaque: *mut c_void,
+    ) -> c_int {
+        let opaque = &mut *(qjs::JS_GetRuntimeOpaque(rt) as *mut Opaque);
+        let interrupt = match opaque.interrupt_handler.as_mut() {
+            Some(handler) => handler(),
+            None => false,
+        };
+        if interrupt {
+            opaque.interrupted.store(true, Ordering::Relaxed);
+        }
+        interrupt as c_int
+    }
+
+    /// Advance the runtime's epoch counter by one tick.
+    ///
+    /// Meant to be called cheaply from an external timer thread (or, under
+    /// the `parallel` feature, any thread holding the lock) to drive
+    /// deadlines armed with [`Runtime::set_epoch_deadline`] without the
+    /// interpreter having to take a timestamp on every instruction.
+    ///
+    /// Deliberately does not lock `inner`: a script stuck in an infinite
+    /// loop holds that lock for the entire run, and the whole point of this
+    /// counter is to let an external thread abort it anyway.
+    pub fn increment_epoch(&self) {
+        self.epoch.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Arm a deadline `ticks` epochs from now, aborting any script still
+    /// running once the epoch counter reaches it.
+    ///
+    /// Internally this installs an [`Runtime::set_interrupt_handler`] that
+    /// compares the current epoch against the deadline, replacing any
+    /// previously installed handler.
+    pub fn set_epoch_deadline(&self, ticks: u64) {
+        let epoch = self.epoch.clone();
+        let deadline = epoch.load(Ordering::Relaxed).saturating_add(ticks);
+        self.set_interrupt_handler(move || epoch.load(Ordering::Relaxed) >= deadline);
+    }
+
     /// Manually run the garbage collection.
     ///
     /// Most of quickjs values are reference counted and
@@ -272,6 +512,8 @@ impl Runtime {
     pub fn run_gc(&self) {
         let guard = self.inner.lock();
         unsafe { qjs::JS_RunGC(guard.rt) };
+        unsafe { guard.get_opaque().metrics.gc_runs.fetch_add(1, Ordering::Relaxed) };
+        guard.sample_peak_memory();
         mem::drop(guard);
     }
 
@@ -284,6 +526,26 @@ impl Runtime {
         unsafe { stats.assume_init() }
     }
 
+    /// Get a snapshot of the runtime's cumulative metrics.
+    ///
+    /// This only reads the counters; it does not itself sample memory
+    /// usage, so `peak_memory` reflects the high-water mark as of the last
+    /// job run by `execute_pending_job` or the last `run_gc` call rather
+    /// than the instant this is called. Call `run_gc` (or pump pending jobs)
+    /// first if you need a fresher sample.
+    pub fn metrics(&self) -> RuntimeMetrics {
+        let guard = self.inner.lock();
+        let metrics = unsafe { &guard.get_opaque().metrics };
+        let snapshot = RuntimeMetrics {
+            jobs_executed: metrics.jobs_executed.load(Ordering::Relaxed),
+            jobs_failed: metrics.jobs_failed.load(Ordering::Relaxed),
+            gc_runs: metrics.gc_runs.load(Ordering::Relaxed),
+            peak_memory: metrics.peak_memory.load(Ordering::Relaxed),
+        };
+        mem::drop(guard);
+        snapshot
+    }
+
     /// Test for pending jobs
     ///
     /// Returns true when at least one job is pending.
@@ -327,4 +589,42 @@ mod test {
         rt.set_gc_threshold(0xFF);
         rt.run_gc();
     }
+
+    #[test]
+    fn epoch_deadline() {
+        let rt = Runtime::new().unwrap();
+        rt.set_epoch_deadline(2);
+        rt.increment_epoch();
+        rt.increment_epoch();
+        // There's no script to actually run here (`Ctx::eval` lives outside
+        // this file), so exercise the same path QuickJS would take once the
+        // deadline is reached: polling the handler through the trampoline
+        // it registered, and checking that it both reports "abort" and
+        // marks the runtime as interrupted for `execute_pending_job` to see.
+        let guard = rt.inner.lock();
+        let aborted = unsafe { Runtime::interrupt_trampoline(guard.rt, guard.rt as *mut c_void) };
+        assert_eq!(aborted, 1, "handler should report the deadline as reached");
+        assert!(unsafe { guard.get_opaque().interrupted.swap(false, Ordering::Relaxed) });
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "re-entrant")]
+    fn reentrancy_guard_panics_on_reentry() {
+        let info = Some(CString::new("test runtime").unwrap());
+        let _outer = ReentrancyGuard::enter(&info);
+        let _inner = ReentrancyGuard::enter(&info);
+    }
+
+    #[test]
+    fn metrics() {
+        let rt = Runtime::new().unwrap();
+        rt.run_gc();
+        rt.run_gc();
+        let metrics = rt.metrics();
+        assert_eq!(metrics.gc_runs, 2);
+        // `run_gc` samples memory usage itself now, so the high-water mark
+        // should already be populated without polling `metrics` in a loop.
+        assert!(metrics.peak_memory > 0);
+    }
 }